@@ -1,30 +1,377 @@
 use clap::{App, Arg, SubCommand};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::{env::current_dir, process::Command};
+use std::{env::current_dir, fs, process::Command};
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 struct StatusOutput {
     #[serde(rename = "Changes", default)]
     pub changes: Changes,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 struct Changes {
     #[serde(rename = "Change", default)]
     pub changes: Vec<Change>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 struct Change {
     #[serde(rename = "Path", default)]
     pub path: String,
     #[serde(rename = "PrintableSize", default)]
     pub size: String,
+    #[serde(rename = "Type", default)]
+    pub change_type: ChangeType,
+    // Set for `Moved`/`LocalMoved` changes; the path the item moved from.
+    #[serde(rename = "OldPath", default)]
+    pub old_path: Option<String>,
 }
 
 impl ToString for Change {
     fn to_string(&self) -> String {
-        format!("File `{}` of size: {}", self.path, self.size)
+        format!(
+            "{:?} file `{}` of size: {}",
+            self.change_type, self.path, self.size
+        )
+    }
+}
+
+/// The kind of pending change Plastic reports in `cm status --xml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+enum ChangeType {
+    Added,
+    Checkout,
+    Changed,
+    Deleted,
+    Moved,
+    LocalMoved,
+    #[serde(other)]
+    #[default]
+    Other,
+}
+
+impl ChangeType {
+    /// The lowercase category name used by `--only`/`--skip`.
+    fn category(&self) -> &'static str {
+        match self {
+            ChangeType::Added => "added",
+            ChangeType::Checkout => "checkout",
+            ChangeType::Changed => "changed",
+            ChangeType::Deleted => "deleted",
+            ChangeType::Moved => "moved",
+            ChangeType::LocalMoved => "localmoved",
+            ChangeType::Other => "other",
+        }
+    }
+
+    fn is_moved(&self) -> bool {
+        matches!(self, ChangeType::Moved | ChangeType::LocalMoved)
+    }
+}
+
+/// A single glob pattern (`*`, `**`, `?` and anchored path prefixes).
+#[derive(Debug, Clone)]
+struct GlobPattern {
+    pattern: String,
+}
+
+impl GlobPattern {
+    fn new(pattern: &str) -> Self {
+        GlobPattern {
+            pattern: pattern.to_owned(),
+        }
+    }
+
+    fn is_match(&self, path: &str) -> bool {
+        glob_match(self.pattern.as_bytes(), path.as_bytes())
+    }
+}
+
+/// Matches a glob pattern against a path, supporting `*` (any run of
+/// characters except `/`), `**` (any run of characters, including `/`)
+/// and `?` (a single character). Every pattern is also an anchored
+/// prefix: once it is used up, the rest of the path only has to start a
+/// new segment, so `src` matches `src/main.rs` and `src/*` matches
+/// `src/sub/main.rs`.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        // The pattern is exhausted: an exact match, or an anchored
+        // prefix match if the remaining text starts a new path segment.
+        None => matches!(text.first(), None | Some(b'/')),
+        Some(b'*') => {
+            if pattern.get(1) == Some(&b'*') {
+                let rest = &pattern[2..];
+                (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+            } else {
+                let rest = &pattern[1..];
+                (0..=text.len())
+                    .take_while(|&i| i == 0 || text[i - 1] != b'/')
+                    .any(|i| glob_match(rest, &text[i..]))
+            }
+        }
+        Some(b'?') if !text.is_empty() => glob_match(&pattern[1..], &text[1..]),
+        Some(&c) => !text.is_empty() && text[0] == c && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Combines `--include`/`--exclude` globs into a single matcher: a path
+/// is undone only if it matches an include pattern (or none were given)
+/// and does not match any exclude pattern.
+#[derive(Debug, Clone, Default)]
+struct Matcher {
+    include: Vec<GlobPattern>,
+    exclude: Vec<GlobPattern>,
+}
+
+impl Matcher {
+    fn new(include: Vec<String>, exclude: Vec<String>) -> Self {
+        Matcher {
+            include: include.iter().map(|p| GlobPattern::new(p)).collect(),
+            exclude: exclude.iter().map(|p| GlobPattern::new(p)).collect(),
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|p| p.is_match(path));
+        let excluded = self.exclude.iter().any(|p| p.is_match(path));
+        included && !excluded
+    }
+}
+
+/// The Plastic XML reports full paths; normalize them relative to
+/// `working_dir` so they line up with user-supplied glob patterns.
+fn relative_to_working_dir<'a>(working_dir: &str, path: &'a str) -> &'a str {
+    path.strip_prefix(working_dir)
+        .map(|rest| rest.trim_start_matches(['/', '\\']))
+        .unwrap_or(path)
+}
+
+/// Selects which `ChangeType` categories `--only`/`--skip` let through.
+#[derive(Debug, Clone, Default)]
+struct CategoryFilter {
+    only: Vec<String>,
+    skip: Vec<String>,
+}
+
+impl CategoryFilter {
+    fn new(only: Vec<String>, skip: Vec<String>) -> Self {
+        CategoryFilter {
+            only: only.iter().map(|c| c.to_lowercase()).collect(),
+            skip: skip.iter().map(|c| c.to_lowercase()).collect(),
+        }
+    }
+
+    fn allows(&self, category: &str) -> bool {
+        let included = self.only.is_empty() || self.only.iter().any(|c| c == category);
+        let skipped = self.skip.iter().any(|c| c == category);
+        included && !skipped
+    }
+}
+
+fn parse_categories(value: Option<&str>) -> Vec<String> {
+    value
+        .map(|value| value.split(',').map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Human,
+    Json,
+}
+
+/// Flags shared by `ensure` and `update`.
+struct Options {
+    verbose: bool,
+    log: bool,
+    jobs: Option<usize>,
+    format: Format,
+    categories: CategoryFilter,
+    matcher: Matcher,
+    verify: bool,
+    hash: HashAlgorithm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HashAlgorithm {
+    Md5,
+    Sha1,
+}
+
+impl HashAlgorithm {
+    fn fileinfo_format(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "{size}|{md5}",
+            HashAlgorithm::Sha1 => "{size}|{sha1}",
+        }
+    }
+
+    fn command(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5sum",
+            HashAlgorithm::Sha1 => "sha1sum",
+        }
+    }
+}
+
+/// Prints a `--verbose` diagnostic line. In `--format json` the only
+/// thing allowed on stdout is the final report, so diagnostics go to
+/// stderr instead.
+fn emit_verbose(options: &Options, line: &str) {
+    if !options.verbose {
+        return;
+    }
+    match options.format {
+        Format::Human => println!("{}", line),
+        Format::Json => eprintln!("{}", line),
+    }
+}
+
+/// Prints a `--log` diagnostic line, subject to the same stdout/stderr
+/// routing as `emit_verbose`.
+fn emit_log(options: &Options, line: &str) {
+    if !options.log {
+        return;
+    }
+    match options.format {
+        Format::Human => println!("{}", line),
+        Format::Json => eprintln!("{}", line),
+    }
+}
+
+/// A single `cm undo` invocation, covering one or more paths.
+#[derive(Debug, Serialize)]
+struct UndoReport {
+    paths: Vec<String>,
+    sizes: Vec<String>,
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+/// Accumulates everything `ensure_clean` observed, so it can be
+/// serialized as a single JSON document once the run is done.
+#[derive(Debug, Default)]
+struct Report {
+    initial_status: Option<StatusOutput>,
+    undos: Vec<UndoReport>,
+    iterations: usize,
+    verify: Option<VerifyReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct Summary {
+    files_undone: usize,
+    iterations: usize,
+    updated: bool,
+}
+
+/// A single pending change, re-keyed from `Change`'s XML field names
+/// (`Path`, `PrintableSize`, ...) to the snake_case this tool's JSON
+/// output otherwise uses throughout.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct ChangeReport {
+    path: String,
+    size: String,
+    change_type: ChangeType,
+    old_path: Option<String>,
+}
+
+impl From<Change> for ChangeReport {
+    fn from(change: Change) -> Self {
+        ChangeReport {
+            path: change.path,
+            size: change.size,
+            change_type: change.change_type,
+            old_path: change.old_path,
+        }
+    }
+}
+
+/// The workspace status at the start of the run, independent of the
+/// XML-oriented `StatusOutput` used to deserialize `cm status --xml`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+struct StatusReport {
+    changes: Vec<ChangeReport>,
+}
+
+impl From<StatusOutput> for StatusReport {
+    fn from(status: StatusOutput) -> Self {
+        StatusReport {
+            changes: status
+                .changes
+                .changes
+                .into_iter()
+                .map(ChangeReport::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JsonReport {
+    initial_status: Option<StatusReport>,
+    undos: Vec<UndoReport>,
+    summary: Summary,
+    verify: Option<VerifyReport>,
+}
+
+impl Report {
+    fn into_json(self, updated: bool) -> JsonReport {
+        let files_undone = self.undos.iter().map(|undo| undo.paths.len()).sum();
+        JsonReport {
+            initial_status: self.initial_status.map(StatusReport::from),
+            undos: self.undos,
+            summary: Summary {
+                files_undone,
+                iterations: self.iterations,
+                updated,
+            },
+            verify: self.verify,
+        }
+    }
+}
+
+/// One workspace file whose on-disk content didn't match what `cm`
+/// reports as the expected revision.
+#[derive(Debug, Clone, Serialize)]
+struct Mismatch {
+    path: String,
+    expected_size: u64,
+    actual_size: Option<u64>,
+    expected_hash: String,
+    actual_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct VerifyReport {
+    hash: &'static str,
+    checked: usize,
+    corrupt: Vec<Mismatch>,
+    // Files whose hash couldn't be computed (e.g. the hashing binary is
+    // missing) — neither confirmed good nor corrupt.
+    unverified: Vec<String>,
+}
+
+/// Prints the final report (JSON mode) or the closing `* Done!` line
+/// (human mode, when `--verbose` was given).
+fn finish(options: &Options, report: Report, updated: bool) {
+    match options.format {
+        Format::Json => {
+            let report = report.into_json(updated);
+            println!(
+                "{}",
+                serde_json::to_string(&report).expect("Cannot serialize report")
+            );
+        }
+        Format::Human => {
+            if options.verbose {
+                println!("* Done!");
+            }
+        }
     }
 }
 
@@ -56,6 +403,51 @@ fn main() {
                         .short("w")
                         .required(false)
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .short("j")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .required(false)
+                        .takes_value(true)
+                        .possible_values(&["human", "json"])
+                        .default_value("human"),
+                )
+                .arg(
+                    Arg::with_name("only")
+                        .long("only")
+                        .required(false)
+                        .takes_value(true)
+                        .help("Comma-separated change categories to undo, e.g. `added,deleted`"),
+                )
+                .arg(
+                    Arg::with_name("skip")
+                        .long("skip")
+                        .required(false)
+                        .takes_value(true)
+                        .help("Comma-separated change categories to leave untouched"),
                 ),
         )
         .subcommand(
@@ -81,6 +473,66 @@ fn main() {
                         .short("w")
                         .required(false)
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .short("j")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .required(false)
+                        .takes_value(true)
+                        .possible_values(&["human", "json"])
+                        .default_value("human"),
+                )
+                .arg(
+                    Arg::with_name("only")
+                        .long("only")
+                        .required(false)
+                        .takes_value(true)
+                        .help("Comma-separated change categories to undo, e.g. `added,deleted`"),
+                )
+                .arg(
+                    Arg::with_name("skip")
+                        .long("skip")
+                        .required(false)
+                        .takes_value(true)
+                        .help("Comma-separated change categories to leave untouched"),
+                )
+                .arg(
+                    Arg::with_name("verify")
+                        .long("verify")
+                        .required(false)
+                        .takes_value(false)
+                        .help("Verify workspace file content against `cm` after updating"),
+                )
+                .arg(
+                    Arg::with_name("hash")
+                        .long("hash")
+                        .required(false)
+                        .takes_value(true)
+                        .possible_values(&["md5", "sha1"])
+                        .default_value("md5"),
                 ),
         )
         .get_matches();
@@ -93,12 +545,9 @@ fn main() {
                     .to_string_lossy()
                     .into_owned(),
             };
-            let verbose = matches.is_present("verbose");
-            let log = matches.is_present("log");
-            ensure_clean(&working_dir, verbose, log);
-            if verbose {
-                println!("* Done!");
-            }
+            let options = parse_options(matches);
+            let report = ensure_clean(&working_dir, &options);
+            finish(&options, report, false);
         }
         ("update", Some(matches)) => {
             let working_dir = match matches.value_of("working-dir") {
@@ -108,46 +557,312 @@ fn main() {
                     .to_string_lossy()
                     .into_owned(),
             };
-            let verbose = matches.is_present("verbose");
-            let log = matches.is_present("log");
-            update(&working_dir, verbose, log);
-            if verbose {
-                println!("* Done!");
+            let options = parse_options(matches);
+            let report = update(&working_dir, &options);
+            let has_corrupt_files = report
+                .verify
+                .as_ref()
+                .map(|verify| !verify.corrupt.is_empty())
+                .unwrap_or(false);
+            finish(&options, report, true);
+            if has_corrupt_files {
+                std::process::exit(1);
             }
         }
         _ => {}
     }
 }
 
-fn update(working_dir: &str, verbose: bool, log: bool) {
-    ensure_clean(working_dir, verbose, log);
-    update_latest(working_dir, verbose, log);
-    ensure_clean(working_dir, verbose, log);
+fn parse_options(matches: &clap::ArgMatches) -> Options {
+    let jobs = matches.value_of("jobs").map(|value| {
+        value
+            .parse::<usize>()
+            .expect("`--jobs` must be a positive number")
+    });
+    let include = matches
+        .values_of("include")
+        .map(|values| values.map(str::to_owned).collect())
+        .unwrap_or_default();
+    let exclude = matches
+        .values_of("exclude")
+        .map(|values| values.map(str::to_owned).collect())
+        .unwrap_or_default();
+    let format = match matches.value_of("format") {
+        Some("json") => Format::Json,
+        _ => Format::Human,
+    };
+    let categories = CategoryFilter::new(
+        parse_categories(matches.value_of("only")),
+        parse_categories(matches.value_of("skip")),
+    );
+    let hash = match matches.value_of("hash") {
+        Some("sha1") => HashAlgorithm::Sha1,
+        _ => HashAlgorithm::Md5,
+    };
+    Options {
+        verbose: matches.is_present("verbose"),
+        log: matches.is_present("log"),
+        jobs,
+        format,
+        matcher: Matcher::new(include, exclude),
+        categories,
+        verify: matches.is_present("verify"),
+        hash,
+    }
 }
 
-fn ensure_clean(working_dir: &str, verbose: bool, log: bool) {
-    if verbose {
-        println!("* Ensure clean workspace");
+fn update(working_dir: &str, options: &Options) -> Report {
+    let mut report = ensure_clean(working_dir, options);
+    update_latest(working_dir, options);
+    let after = ensure_clean(working_dir, options);
+    report.undos.extend(after.undos);
+    report.iterations += after.iterations;
+    if options.verify {
+        report.verify = Some(verify_workspace(working_dir, options));
     }
-    loop {
-        let status = get_status(working_dir, verbose, log);
-        if status.changes.changes.is_empty() {
-            break;
+    report
+}
+
+/// Retries a per-file redownload this many times before giving up on it.
+const VERIFY_RETRY_LIMIT: u32 = 3;
+
+/// Confirms every workspace file matches the content `cm` expects after
+/// `cm update --forced`, redownloading and re-checking files that don't.
+/// Each file spawns a handful of processes (`cm fileinfo`, a hash binary,
+/// possibly `cm update --forced`), so, like `cleanup`, the work is
+/// chunked across a thread pool instead of running one file at a time.
+fn verify_workspace(working_dir: &str, options: &Options) -> VerifyReport {
+    emit_verbose(options, "* Verify workspace content");
+    let paths = list_workspace_files(working_dir);
+    let num_jobs = options.jobs.unwrap_or_else(rayon::current_num_threads).max(1);
+    let chunk_size = paths.len().div_ceil(num_jobs);
+    let chunks: Vec<&[String]> = paths.chunks(chunk_size.max(1)).collect();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_jobs)
+        .build()
+        .expect("Cannot build thread pool");
+    let results: Vec<(Vec<Mismatch>, Vec<String>)> = pool.install(|| {
+        chunks
+            .par_iter()
+            .map(|chunk| verify_chunk(chunk, working_dir, options))
+            .collect()
+    });
+    let mut corrupt = Vec::new();
+    let mut unverified = Vec::new();
+    for (chunk_corrupt, chunk_unverified) in results {
+        corrupt.extend(chunk_corrupt);
+        unverified.extend(chunk_unverified);
+    }
+    VerifyReport {
+        hash: match options.hash {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+        },
+        checked: paths.len(),
+        corrupt,
+        unverified,
+    }
+}
+
+/// Checks (and retries) every path in `chunk`, returning the files found
+/// corrupt and the files that couldn't be verified at all.
+fn verify_chunk(
+    chunk: &[String],
+    working_dir: &str,
+    options: &Options,
+) -> (Vec<Mismatch>, Vec<String>) {
+    let mut corrupt = Vec::new();
+    let mut unverified = Vec::new();
+    for path in chunk {
+        let mut outcome = check_file(working_dir, path, options);
+        let mut attempt = 0;
+        while matches!(outcome, CheckOutcome::Mismatch(_)) && attempt < VERIFY_RETRY_LIMIT {
+            attempt += 1;
+            emit_verbose(options, &format!("* Redownloading `{}` (attempt {})", path, attempt));
+            redownload_file(working_dir, path, options);
+            outcome = check_file(working_dir, path, options);
         }
-        if verbose {
-            println!("* Workspace has pending changes:");
-            for change in &status.changes.changes {
-                println!("- {}", change.to_string());
+        match outcome {
+            CheckOutcome::Match => {}
+            CheckOutcome::Mismatch(mismatch) => {
+                eprintln!(
+                    "! `{}` is corrupt after {} attempt(s): expected {} bytes ({}), found {:?} bytes ({:?})",
+                    mismatch.path,
+                    VERIFY_RETRY_LIMIT,
+                    mismatch.expected_size,
+                    mismatch.expected_hash,
+                    mismatch.actual_size,
+                    mismatch.actual_hash
+                );
+                corrupt.push(mismatch);
+            }
+            CheckOutcome::Unverifiable(reason) => {
+                eprintln!("! Could not verify `{}`: {}", path, reason);
+                unverified.push(path.clone());
             }
         }
-        cleanup(&status.changes.changes, working_dir, verbose, log);
     }
+    (corrupt, unverified)
+}
+
+/// Lists the paths `cm ls --recursive` reports that exist on disk as
+/// regular files. Directories (which have no content to verify) and paths
+/// missing from disk are skipped.
+fn list_workspace_files(working_dir: &str) -> Vec<String> {
+    let output = Command::new("cm")
+        .arg("ls")
+        .arg("--recursive")
+        .arg("--format={path}")
+        .current_dir(working_dir)
+        .output()
+        .expect("Error during `cm ls`");
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .filter(|path| std::path::Path::new(working_dir).join(path).is_file())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The result of comparing `path`'s on-disk content against what `cm
+/// fileinfo` reports as the expected revision.
+enum CheckOutcome {
+    /// Size and hash both matched.
+    Match,
+    /// Size and/or hash differ from what `cm` expects.
+    Mismatch(Mismatch),
+    /// Couldn't be checked at all (e.g. the hashing binary is missing),
+    /// so it's neither confirmed good nor known corrupt.
+    Unverifiable(String),
+}
+
+fn check_file(working_dir: &str, path: &str, options: &Options) -> CheckOutcome {
+    let (expected_size, expected_hash) = match fileinfo(working_dir, path, options.hash) {
+        Ok(fileinfo) => fileinfo,
+        Err(reason) => return CheckOutcome::Unverifiable(reason),
+    };
+    let full_path = std::path::Path::new(working_dir).join(path);
+    let actual_size = fs::metadata(&full_path).ok().map(|meta| meta.len());
+    if actual_size != Some(expected_size) {
+        return CheckOutcome::Mismatch(Mismatch {
+            path: path.to_owned(),
+            expected_size,
+            actual_size,
+            expected_hash,
+            actual_hash: None,
+        });
+    }
+    let actual_hash = match hash_file(&full_path, options.hash) {
+        Ok(hash) => hash,
+        Err(reason) => return CheckOutcome::Unverifiable(reason),
+    };
+    if actual_hash == expected_hash {
+        CheckOutcome::Match
+    } else {
+        CheckOutcome::Mismatch(Mismatch {
+            path: path.to_owned(),
+            expected_size,
+            actual_size,
+            expected_hash,
+            actual_hash: Some(actual_hash),
+        })
+    }
+}
+
+/// Looks up the size and expected hash `cm` has on record for `path`.
+/// Returns `Err` instead of panicking when `cm` can't report on it (e.g.
+/// a private/untracked file `cm ls --recursive` still lists), so one
+/// stray path can't take down the whole `--verify` run.
+fn fileinfo(working_dir: &str, path: &str, hash: HashAlgorithm) -> Result<(u64, String), String> {
+    let output = Command::new("cm")
+        .arg("fileinfo")
+        .arg(format!("--format={}", hash.fileinfo_format()))
+        .arg(path)
+        .current_dir(working_dir)
+        .output()
+        .map_err(|err| format!("could not run `cm fileinfo`: {}", err))?;
+    let contents = String::from_utf8_lossy(&output.stdout);
+    let line = contents.trim();
+    let (size, hash_value) = line
+        .split_once('|')
+        .ok_or_else(|| format!("cannot parse `cm fileinfo` output `{}`", line))?;
+    let size = size
+        .parse()
+        .map_err(|_| format!("cannot parse size `{}`", size))?;
+    Ok((size, hash_value.to_owned()))
+}
+
+/// Runs the configured hashing binary over `path`. Returns `Err` when the
+/// hash couldn't be computed at all (binary missing, process failed, or
+/// unparsable output) rather than silently treating that as a mismatch.
+fn hash_file(path: &std::path::Path, hash: HashAlgorithm) -> Result<String, String> {
+    let output = Command::new(hash.command())
+        .arg(path)
+        .output()
+        .map_err(|err| format!("could not run `{}`: {}", hash.command(), err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "`{}` exited with {:?}",
+            hash.command(),
+            output.status.code()
+        ));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(str::to_owned)
+        .ok_or_else(|| format!("could not parse `{}` output", hash.command()))
+}
+
+fn redownload_file(working_dir: &str, path: &str, options: &Options) {
+    let output = Command::new("cm")
+        .arg("update")
+        .arg(path)
+        .arg("--forced")
+        .current_dir(working_dir)
+        .output()
+        .expect("Error during `cm update`");
+    let contents = String::from_utf8_lossy(&output.stdout);
+    emit_log(options, &format!("* STDOUT: `{}`", contents));
+    let contents = String::from_utf8_lossy(&output.stderr);
+    emit_log(options, &format!("* STDERR: `{}`", contents));
 }
 
-fn get_status(working_dir: &str, verbose: bool, log: bool) -> StatusOutput {
-    if verbose {
-        println!("* Get workspace status");
+fn ensure_clean(working_dir: &str, options: &Options) -> Report {
+    emit_verbose(options, "* Ensure clean workspace");
+    let mut report = Report::default();
+    loop {
+        let status = get_status(working_dir, options);
+        if report.initial_status.is_none() {
+            report.initial_status = Some(status.clone());
+        }
+        let changes: Vec<Change> = status
+            .changes
+            .changes
+            .into_iter()
+            .filter(|change| {
+                options
+                    .matcher
+                    .matches(relative_to_working_dir(working_dir, &change.path))
+                    && options.categories.allows(change.change_type.category())
+            })
+            .collect();
+        if changes.is_empty() {
+            break;
+        }
+        report.iterations += 1;
+        emit_verbose(options, "* Workspace has pending changes:");
+        for change in &changes {
+            emit_verbose(options, &format!("- {}", change.to_string()));
+        }
+        report.undos.extend(cleanup(&changes, working_dir, options));
     }
+    report
+}
+
+fn get_status(working_dir: &str, options: &Options) -> StatusOutput {
+    emit_verbose(options, "* Get workspace status");
     let output = Command::new("cm")
         .arg("status")
         .arg("--xml")
@@ -155,48 +870,102 @@ fn get_status(working_dir: &str, verbose: bool, log: bool) -> StatusOutput {
         .current_dir(working_dir)
         .output()
         .expect("Error during `cm status`");
-    if log {
-        let contents = String::from_utf8_lossy(&output.stdout);
-        println!("* STDOUT: `{}`", contents);
-        let contents = String::from_utf8_lossy(&output.stderr);
-        println!("* STDERR: `{}`", contents);
-    }
+    let contents = String::from_utf8_lossy(&output.stdout);
+    emit_log(options, &format!("* STDOUT: `{}`", contents));
+    let contents = String::from_utf8_lossy(&output.stderr);
+    emit_log(options, &format!("* STDERR: `{}`", contents));
     let contents = String::from_utf8_lossy(&output.stdout);
     serde_xml_rs::from_str::<StatusOutput>(&contents)
         .expect(&format!("Cannot deserialize `{}`", contents))
 }
 
-fn cleanup(changes: &[Change], working_dir: &str, verbose: bool, log: bool) {
-    if verbose {
-        println!("* Undo changes");
-    }
-    for change in changes {
-        undo(change, working_dir, verbose, log);
+fn cleanup(changes: &[Change], working_dir: &str, options: &Options) -> Vec<UndoReport> {
+    emit_verbose(options, "* Undo changes");
+    let num_jobs = options.jobs.unwrap_or_else(rayon::current_num_threads).max(1);
+    let chunk_size = changes.len().div_ceil(num_jobs);
+    let chunks: Vec<&[Change]> = changes.chunks(chunk_size.max(1)).collect();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_jobs)
+        .build()
+        .expect("Cannot build thread pool");
+    let reports: Vec<UndoReport> = pool.install(|| {
+        chunks
+            .par_iter()
+            .map(|chunk| undo_chunk(chunk, working_dir, options))
+            .collect()
+    });
+    for report in &reports {
+        if report.exit_code != Some(0) {
+            eprintln!(
+                "! `cm undo` exited with {:?} for {} path(s): {}",
+                report.exit_code,
+                report.paths.len(),
+                report.stderr
+            );
+        }
     }
+    reports
 }
 
-fn undo(change: &Change, working_dir: &str, verbose: bool, log: bool) {
-    if verbose {
-        println!("* Undo change: {}", change.to_string());
+fn undo_chunk(chunk: &[Change], working_dir: &str, options: &Options) -> UndoReport {
+    let paths: Vec<String> = chunk.iter().map(|change| change.path.clone()).collect();
+    let sizes: Vec<String> = chunk.iter().map(|change| change.size.clone()).collect();
+    if chunk.is_empty() {
+        return UndoReport {
+            paths,
+            sizes,
+            exit_code: Some(0),
+            stdout: String::new(),
+            stderr: String::new(),
+        };
     }
-    let output = Command::new("cm")
+    for change in chunk {
+        emit_verbose(options, &format!("* Undo change: {}", change.to_string()));
+    }
+    // A move is really two paths (the old location and the new one); undo
+    // both, or the workspace is left with a dangling half-moved item.
+    let undo_paths: Vec<&str> = chunk
+        .iter()
+        .flat_map(|change| {
+            let old_path = if change.change_type.is_moved() {
+                change.old_path.as_deref()
+            } else {
+                None
+            };
+            old_path.into_iter().chain(std::iter::once(change.path.as_str()))
+        })
+        .collect();
+    let result = Command::new("cm")
         .arg("undo")
-        .arg(&change.path)
+        .args(undo_paths)
         .current_dir(working_dir)
-        .output()
-        .expect("Error during `cm undo`");
-    if log {
-        let contents = String::from_utf8_lossy(&output.stdout);
-        println!("* STDOUT: `{}`", contents);
-        let contents = String::from_utf8_lossy(&output.stderr);
-        println!("* STDERR: `{}`", contents);
+        .output();
+    match result {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            emit_log(options, &format!("* STDOUT: `{}`", stdout));
+            emit_log(options, &format!("* STDERR: `{}`", stderr));
+            UndoReport {
+                paths,
+                sizes,
+                exit_code: output.status.code(),
+                stdout,
+                stderr,
+            }
+        }
+        Err(err) => UndoReport {
+            paths,
+            sizes,
+            exit_code: None,
+            stdout: String::new(),
+            stderr: format!("Error during `cm undo`: {}", err),
+        },
     }
 }
 
-fn update_latest(working_dir: &str, verbose: bool, log: bool) {
-    if verbose {
-        println!("* Update workspace");
-    }
+fn update_latest(working_dir: &str, options: &Options) {
+    emit_verbose(options, "* Update workspace");
     let output = Command::new("cm")
         .arg("update")
         .arg("--last")
@@ -207,10 +976,144 @@ fn update_latest(working_dir: &str, verbose: bool, log: bool) {
         .stderr(std::process::Stdio::piped())
         .output()
         .expect("Error during `cm update`");
-    if log {
-        let contents = String::from_utf8_lossy(&output.stdout);
-        println!("* STDOUT: `{}`", contents);
-        let contents = String::from_utf8_lossy(&output.stderr);
-        println!("* STDERR: `{}`", contents);
+    let contents = String::from_utf8_lossy(&output.stdout);
+    emit_log(options, &format!("* STDOUT: `{}`", contents));
+    let contents = String::from_utf8_lossy(&output.stderr);
+    emit_log(options, &format!("* STDERR: `{}`", contents));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_exact() {
+        assert!(glob_match(b"src/main.rs", b"src/main.rs"));
+        assert!(!glob_match(b"src/main.rs", b"src/lib.rs"));
+    }
+
+    #[test]
+    fn glob_match_anchored_prefix() {
+        assert!(glob_match(b"src", b"src/main.rs"));
+        assert!(!glob_match(b"sr", b"src/main.rs"));
+    }
+
+    #[test]
+    fn glob_match_star_does_not_cross_slash() {
+        assert!(glob_match(b"src/*.rs", b"src/main.rs"));
+        assert!(!glob_match(b"src/*.rs", b"src/sub/main.rs"));
+    }
+
+    #[test]
+    fn glob_match_double_star_crosses_slash() {
+        assert!(glob_match(b"src/**/*.rs", b"src/sub/main.rs"));
+        assert!(glob_match(b"src/**", b"src/sub/main.rs"));
+    }
+
+    #[test]
+    fn glob_match_question_mark() {
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(!glob_match(b"a?c", b"ac"));
+    }
+
+    #[test]
+    fn matcher_include_only() {
+        let matcher = Matcher::new(vec!["src/*.rs".to_owned()], vec![]);
+        assert!(matcher.matches("src/main.rs"));
+        assert!(!matcher.matches("docs/readme.md"));
+    }
+
+    #[test]
+    fn matcher_exclude_only() {
+        let matcher = Matcher::new(vec![], vec!["**/*.generated.rs".to_owned()]);
+        assert!(matcher.matches("src/main.rs"));
+        assert!(!matcher.matches("src/main.generated.rs"));
+    }
+
+    #[test]
+    fn matcher_include_and_exclude_is_an_intersection() {
+        let matcher = Matcher::new(
+            vec!["src/**".to_owned()],
+            vec!["src/*.generated.rs".to_owned()],
+        );
+        assert!(matcher.matches("src/main.rs"));
+        assert!(!matcher.matches("src/main.generated.rs"));
+        assert!(!matcher.matches("docs/readme.md"));
+    }
+
+    #[test]
+    fn matcher_with_no_patterns_matches_everything() {
+        let matcher = Matcher::new(vec![], vec![]);
+        assert!(matcher.matches("anything"));
+    }
+
+    #[test]
+    fn category_filter_only() {
+        let filter = CategoryFilter::new(vec!["added".to_owned(), "deleted".to_owned()], vec![]);
+        assert!(filter.allows("added"));
+        assert!(filter.allows("deleted"));
+        assert!(!filter.allows("moved"));
+    }
+
+    #[test]
+    fn category_filter_skip() {
+        let filter = CategoryFilter::new(vec![], vec!["moved".to_owned()]);
+        assert!(filter.allows("added"));
+        assert!(!filter.allows("moved"));
+    }
+
+    #[test]
+    fn category_filter_is_case_insensitive() {
+        let filter = CategoryFilter::new(vec!["Added".to_owned()], vec![]);
+        assert!(filter.allows("added"));
+    }
+
+    #[test]
+    fn category_filter_with_no_categories_allows_everything() {
+        let filter = CategoryFilter::new(vec![], vec![]);
+        assert!(filter.allows("anything"));
+    }
+
+    #[test]
+    fn status_output_deserializes_cm_status_xml() {
+        let xml = r#"
+            <StatusOutput>
+                <Changes>
+                    <Change>
+                        <Type>Added</Type>
+                        <Path>/ws/new_file.txt</Path>
+                        <PrintableSize>1 KB</PrintableSize>
+                    </Change>
+                    <Change>
+                        <Type>Moved</Type>
+                        <Path>/ws/renamed.txt</Path>
+                        <OldPath>/ws/original.txt</OldPath>
+                        <PrintableSize>2 KB</PrintableSize>
+                    </Change>
+                    <Change>
+                        <Type>LocalMoved</Type>
+                        <Path>/ws/local_renamed.txt</Path>
+                        <OldPath>/ws/local_original.txt</OldPath>
+                        <PrintableSize>512 bytes</PrintableSize>
+                    </Change>
+                </Changes>
+            </StatusOutput>
+        "#;
+        let status: StatusOutput = serde_xml_rs::from_str(xml).expect("valid `cm status --xml`");
+        let changes = status.changes.changes;
+        assert_eq!(changes.len(), 3);
+
+        assert_eq!(changes[0].change_type, ChangeType::Added);
+        assert_eq!(changes[0].path, "/ws/new_file.txt");
+        assert_eq!(changes[0].old_path, None);
+        assert!(!changes[0].change_type.is_moved());
+
+        assert_eq!(changes[1].change_type, ChangeType::Moved);
+        assert_eq!(changes[1].old_path.as_deref(), Some("/ws/original.txt"));
+        assert!(changes[1].change_type.is_moved());
+
+        assert_eq!(changes[2].change_type, ChangeType::LocalMoved);
+        assert_eq!(changes[2].old_path.as_deref(), Some("/ws/local_original.txt"));
+        assert!(changes[2].change_type.is_moved());
     }
 }